@@ -0,0 +1,35 @@
+extern crate rcc;
+
+use rcc::lexer;
+use rcc::lexer::DepGraph;
+use rcc::source;
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+
+    let mut system = false; // --system: follow <...> headers too
+    let mut input_files: Vec<&String> = Vec::new();
+    for arg in &args[1..] {
+        match arg.as_str() {
+            "--system" => system = true,
+            _ => input_files.push(arg),
+        }
+    }
+    if input_files.is_empty() {
+        println!("Usage: rcc-deps [--system] <input_file>...");
+        return;
+    }
+
+    let graph = Rc::new(RefCell::new(DepGraph::new()));
+    for input_file_name in input_files {
+        let s = source::read_source(Some(input_file_name));
+        graph.borrow_mut().add_node(input_file_name);
+        let mut lexer = lexer::Lexer::new(input_file_name.clone(), &s);
+        lexer.set_dep_graph(graph.clone(), system);
+        while lexer.get().is_some() {}
+    }
+    print!("{}", graph.borrow().to_dot());
+}
@@ -0,0 +1,309 @@
+extern crate rcc;
+
+use rcc::lexer;
+use rcc::lexer::{Token, TokenKind};
+use rcc::source;
+
+const INDENT: &str = "    ";
+
+// a value ends with this token, so a following `-`, `*`, `&`... is binary
+fn ends_operand(tok: &Token) -> bool {
+    match tok.kind {
+        TokenKind::Identifier => !lexer::is_keyword(&tok.val),
+        TokenKind::IntNumber | TokenKind::FloatNumber | TokenKind::String | TokenKind::Char => true,
+        TokenKind::Symbol => tok.val == ")" || tok.val == "]",
+        _ => false,
+    }
+}
+
+fn is_word(tok: &Token) -> bool {
+    matches!(tok.kind, TokenKind::Identifier | TokenKind::IntNumber | TokenKind::FloatNumber)
+}
+
+fn is_symbol(tok: &Token, syms: &[&str]) -> bool {
+    tok.kind == TokenKind::Symbol && syms.contains(&tok.val.as_str())
+}
+
+// whether a space goes between `prev` and `cur` on the same line
+fn space_before(prev: &Token, prev_unary: bool, cur: &Token, in_ternary: bool) -> bool {
+    if prev.is_comment() || cur.is_comment() {
+        return true;
+    }
+    // the lexer splits things like `0x1f` or `1.5f`; keep those together
+    if is_word(prev) && is_word(cur) && !cur.space {
+        return false;
+    }
+    if is_symbol(cur, &[";", ",", ")", "]"]) || is_symbol(prev, &["(", "[", ".", "->"]) {
+        return false;
+    }
+    if is_symbol(cur, &[".", "->"]) || prev_unary {
+        return false;
+    }
+    if is_symbol(cur, &["++", "--"]) && ends_operand(prev) {
+        return false; // postfix
+    }
+    if is_symbol(cur, &["("]) {
+        return match prev.kind {
+            TokenKind::Identifier => lexer::is_keyword(&prev.val) && prev.val != "sizeof",
+            _ => !ends_operand(prev),
+        };
+    }
+    if is_symbol(cur, &["["]) {
+        return !ends_operand(prev);
+    }
+    if is_symbol(cur, &[":"]) && !in_ternary {
+        return false; // label or case
+    }
+    true
+}
+
+fn format_line(line: &[Token], indent: usize) -> String {
+    let mut s = String::new();
+
+    // preprocessor lines keep their own spacing
+    if is_symbol(&line[0], &["#"]) {
+        for (i, tok) in line.iter().enumerate() {
+            if i > 0 && tok.space {
+                s.push(' ');
+            }
+            s.push_str(&tok.spelling());
+        }
+        return s;
+    }
+
+    s.push_str(&INDENT.repeat(indent));
+    let mut prev: Option<&Token> = None;
+    let mut prev_unary = false;
+    let mut prev_operand = false;
+    let mut ternary = 0;
+    for tok in line {
+        let unary = match tok.val.as_str() {
+            "!" | "~" => tok.kind == TokenKind::Symbol,
+            "-" | "+" | "*" | "&" | "++" | "--" => tok.kind == TokenKind::Symbol && !prev_operand,
+            _ => false,
+        };
+        if is_symbol(tok, &["?"]) {
+            ternary += 1;
+        }
+        if let Some(p) = prev {
+            if space_before(p, prev_unary, tok, ternary > 0) {
+                s.push(' ');
+            }
+        }
+        if is_symbol(tok, &[":"]) && ternary > 0 {
+            ternary -= 1;
+        }
+
+        s.push_str(&tok.spelling());
+        prev_operand = if is_symbol(tok, &["++", "--"]) {
+            !unary && prev_operand
+        } else {
+            ends_operand(tok)
+        };
+        prev_unary = unary;
+        prev = Some(tok);
+    }
+    s
+}
+
+// a `{` on its own line belongs at the end of this line
+fn opens_block(line: &[Token]) -> bool {
+    match line.last() {
+        Some(last) => {
+            !is_symbol(&line[0], &["#"])
+                && !last.is_comment()
+                && !is_symbol(last, &[";", "{", "}", ":"])
+        }
+        None => false,
+    }
+}
+
+// K&R braces: `{` ends the line that opens a block, and a block body has one statement
+// per line. brace initializers like `= {1, 2}` are left alone
+fn split_blocks(lines: Vec<Vec<Token>>) -> Vec<Vec<Token>> {
+    let mut out: Vec<Vec<Token>> = Vec::new();
+    let mut blocks: Vec<bool> = Vec::new(); // open braces; true for a block, false for an initializer
+    for line in lines {
+        if line.is_empty() || is_symbol(&line[0], &["#"]) {
+            out.push(line);
+            continue;
+        }
+        let mut cur = match out.last() {
+            Some(prev) if is_symbol(&line[0], &["{"]) && opens_block(prev) => out.pop().unwrap(),
+            _ => Vec::new(),
+        };
+        let mut parens = 0;
+        for (i, tok) in line.iter().enumerate() {
+            let next = line.get(i + 1);
+            let in_block = blocks.last().copied().unwrap_or(true);
+            let mut end_line = false;
+            if is_symbol(tok, &["(", "["]) {
+                parens += 1;
+            } else if is_symbol(tok, &[")", "]"]) {
+                parens -= 1;
+            } else if is_symbol(tok, &["{"]) {
+                let prev = cur.last().or_else(|| out.last().and_then(|l| l.last()));
+                let block = match prev {
+                    Some(p) => !is_symbol(p, &["=", ",", "(", "{"]) || (p.val == "{" && in_block),
+                    None => true,
+                };
+                blocks.push(block);
+                end_line = block;
+            } else if is_symbol(tok, &["}"]) {
+                if blocks.pop().unwrap_or(true) {
+                    if !cur.is_empty() {
+                        out.push(cur);
+                        cur = Vec::new();
+                    }
+                    // `} else`, `};` and `} while (x);` stay together
+                    end_line = !next.is_some_and(|n| {
+                        is_symbol(n, &[";", ","]) || n.val == "else" || n.val == "while"
+                    });
+                }
+            } else if is_symbol(tok, &[";"]) && parens == 0 && in_block {
+                end_line = true;
+            }
+            cur.push(tok.clone());
+            // a trailing comment stays on the line it follows
+            if end_line && next.is_some_and(|n| !n.is_comment()) {
+                out.push(cur);
+                cur = Vec::new();
+            }
+        }
+        if !cur.is_empty() {
+            out.push(cur);
+        }
+    }
+    out
+}
+
+fn format_source(source: &str) -> String {
+    let mut lexer = lexer::Lexer::new("<format>".to_string(), source);
+    lexer.set_retain_comments(true);
+
+    let mut lines: Vec<Vec<Token>> = vec![Vec::new()];
+    while let Some(tok) = lexer.do_read_token() {
+        if tok.kind == TokenKind::Newline {
+            lines.push(Vec::new());
+        } else {
+            lines.last_mut().unwrap().push(tok);
+        }
+    }
+
+    let mut out = String::new();
+    let mut depth: usize = 0;
+    let mut blank = false;
+    for line in &split_blocks(lines) {
+        if line.is_empty() {
+            // collapse runs of blank lines
+            if !blank && !out.is_empty() {
+                out.push('\n');
+            }
+            blank = true;
+            continue;
+        }
+        blank = false;
+
+        let indent = if is_symbol(&line[0], &["}"]) {
+            depth.saturating_sub(1)
+        } else {
+            depth
+        };
+        out.push_str(&format_line(line, indent));
+        out.push('\n');
+
+        for tok in line {
+            if is_symbol(tok, &["{"]) {
+                depth += 1;
+            } else if is_symbol(tok, &["}"]) {
+                depth = depth.saturating_sub(1);
+            }
+        }
+    }
+    // no trailing blank line
+    while out.ends_with("\n\n") {
+        out.pop();
+    }
+    out
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+
+    let s = source::read_source(args.get(1).map(|a| a.as_str()));
+    print!("{}", format_source(&s));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SNIPPET: &str = "\
+#include <stdio.h>
+#define SQ(x) ((x)*(x))
+/* block
+   comment */
+int  main ( void ){
+int a=-1,*p=&a; // line comment
+  a = a*-2 - *p;
+    a = a>0?a:-a;
+  ++a; a--;
+retry:
+switch(a){case 1:goto retry;default:break;}
+return p->x?SQ(a):0;
+}
+";
+
+    #[test]
+    fn format_is_idempotent() {
+        let once = format_source(SNIPPET);
+        assert_eq!(format_source(&once), once);
+    }
+
+    #[test]
+    fn format_brace_on_own_line() {
+        assert_eq!(
+            format_source("int main()\n{\nreturn 0;\n}\n"),
+            "int main() {\n    return 0;\n}\n"
+        );
+        assert_eq!(
+            format_source("int main() {\nreturn 0;\n}\n"),
+            "int main() {\n    return 0;\n}\n"
+        );
+    }
+
+    #[test]
+    fn format_one_line_blocks() {
+        assert_eq!(
+            format_source("if (x) { y++; } else { --z; }\n"),
+            "if (x) {\n    y++;\n} else {\n    --z;\n}\n"
+        );
+        assert_eq!(
+            format_source("do { i++; } while (i < 3); // loop\n"),
+            "do {\n    i++;\n} while (i < 3); // loop\n"
+        );
+        assert_eq!(
+            format_source("for (i = 0; i < n; i++) { s += i; }\n"),
+            "for (i = 0; i < n; i++) {\n    s += i;\n}\n"
+        );
+    }
+
+    #[test]
+    fn format_keeps_initializers() {
+        assert_eq!(
+            format_source("int a[] = {1, 2}, m[2][2] = {{1, 0}, {0, 1}};\n"),
+            "int a[] = { 1, 2 }, m[2][2] = { { 1, 0 }, { 0, 1 } };\n"
+        );
+    }
+
+    #[test]
+    fn format_spacing() {
+        let out = format_source(SNIPPET);
+        assert!(out.contains("    int a = -1, *p = &a; // line comment\n"), "{}", out);
+        assert!(out.contains("    a = a * -2 - *p;\n"), "{}", out);
+        assert!(out.contains("    a = a > 0 ? a : -a;\n"), "{}", out);
+        assert!(out.contains("    retry:\n"), "{}", out);
+        assert!(out.contains("    return p->x ? SQ(a) : 0;\n"), "{}", out);
+        assert!(out.starts_with("#include <stdio.h>\n"), "{}", out);
+    }
+}
@@ -0,0 +1,120 @@
+extern crate rcc;
+
+use rcc::lexer;
+use rcc::lexer::{Token, TokenKind};
+use rcc::source;
+
+const STYLE: &str = "\
+.keyword { color: #0000c0; font-weight: bold; }
+.identifier { color: #000000; }
+.string-literal, .char-literal { color: #a31515; }
+.numeric-literal { color: #098658; }
+.operator { color: #444444; }
+.comment { color: #008000; font-style: italic; }";
+
+// None for tokens that are not wrapped in a span
+fn css_class(tok: &Token) -> Option<&'static str> {
+    match tok.kind {
+        TokenKind::Identifier if lexer::is_keyword(&tok.val) => Some("keyword"),
+        TokenKind::Identifier => Some("identifier"),
+        TokenKind::IntNumber | TokenKind::FloatNumber => Some("numeric-literal"),
+        TokenKind::String => Some("string-literal"),
+        TokenKind::Char => Some("char-literal"),
+        TokenKind::Symbol => Some("operator"),
+        TokenKind::LineComment | TokenKind::BlockComment => Some("comment"),
+        TokenKind::Newline => None,
+    }
+}
+
+fn html_escape(s: &str) -> String {
+    let mut escaped = String::new();
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn highlight(source: &str) -> String {
+    let mut lexer = lexer::Lexer::new("<highlight>".to_string(), source);
+    lexer.set_retain_comments(true);
+
+    let mut body = String::new();
+    while let Some(tok) = lexer.do_read_token() {
+        let class = match css_class(&tok) {
+            Some(class) => class,
+            None => {
+                body.push('\n');
+                continue;
+            }
+        };
+        // keep indentation and alignment as written
+        body.push_str(&tok.whitespace);
+        body.push_str(&format!(
+            "<span class=\"{}\">{}</span>",
+            class,
+            html_escape(&tok.spelling())
+        ));
+    }
+
+    format!(
+        concat!(
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n",
+            "<style>\n{}\n</style>\n</head>\n<body>\n",
+            "<pre><code>{}</code></pre>\n</body>\n</html>\n"
+        ),
+        STYLE, body
+    )
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+
+    let s = source::read_source(args.get(1).map(|a| a.as_str()));
+    print!("{}", highlight(&s));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn highlight_main() {
+        let html = highlight("int main() { return 0; }");
+        let code = concat!(
+            "<pre><code>",
+            "<span class=\"keyword\">int</span>",
+            " <span class=\"identifier\">main</span>",
+            "<span class=\"operator\">(</span>",
+            "<span class=\"operator\">)</span>",
+            " <span class=\"operator\">{</span>",
+            " <span class=\"keyword\">return</span>",
+            " <span class=\"numeric-literal\">0</span>",
+            "<span class=\"operator\">;</span>",
+            " <span class=\"operator\">}</span>",
+            "</code></pre>"
+        );
+        assert!(html.starts_with("<!DOCTYPE html>\n"), "{}", html);
+        assert!(html.contains(code), "{}", html);
+    }
+
+    #[test]
+    fn highlight_keeps_indentation() {
+        let html = highlight("int f(int x) {\n    if (x)\n\t\treturn 0;\n}\n");
+        assert!(html.contains("\n    <span class=\"keyword\">if</span> <span"), "{}", html);
+        assert!(html.contains("\n\t\t<span class=\"keyword\">return</span> <span"), "{}", html);
+    }
+
+    #[test]
+    fn highlight_escapes() {
+        let html = highlight("a<b&&s==\"x\"");
+        assert!(html.contains("<span class=\"operator\">&lt;</span>"), "{}", html);
+        assert!(html.contains("<span class=\"operator\">&amp;&amp;</span>"), "{}", html);
+        assert!(html.contains("<span class=\"string-literal\">&quot;x&quot;</span>"), "{}", html);
+    }
+}
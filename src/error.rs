@@ -1,6 +1,146 @@
+use std::fs::OpenOptions;
+use std::io::Write;
 use std::process;
+use std::sync::Mutex;
 
-pub fn error_exit(line: i32, msg: &str) {
-    println!("error: {}: {}", line, msg);
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum ErrorFormat {
+    Human,
+    Json, // --error-format=json
+}
+
+lazy_static! {
+    static ref ERROR_FORMAT: Mutex<ErrorFormat> = Mutex::new(ErrorFormat::Human);
+    static ref DIAGNOSTICS_OUTPUT: Mutex<Option<String>> = Mutex::new(None);
+    // json warnings wait here so they come out in the same array as the error
+    static ref PENDING: Mutex<Vec<String>> = Mutex::new(Vec::new());
+}
+
+pub fn set_error_format(format: ErrorFormat) {
+    *ERROR_FORMAT.lock().unwrap() = format;
+}
+
+// write json diagnostics to this file instead of stdout
+pub fn set_diagnostics_output(filename: &str) {
+    *DIAGNOSTICS_OUTPUT.lock().unwrap() = Some(filename.to_string());
+}
+
+fn json_escape(s: &str) -> String {
+    let mut escaped = String::new();
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn json_record(file: &str, line: i32, level: &str, msg: &str) -> String {
+    // columns are not tracked by the lexer yet
+    format!(
+        "{{\"file\":\"{}\",\"line\":{},\"col\":0,\"level\":\"{}\",\"message\":\"{}\"}}",
+        json_escape(file),
+        line,
+        level,
+        json_escape(msg)
+    )
+}
+
+fn emit_json(records: &[String]) {
+    let json = format!("[{}]", records.join(","));
+    match *DIAGNOSTICS_OUTPUT.lock().unwrap() {
+        Some(ref filename) => {
+            let mut out = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(filename)
+                .expect("Failed to open the diagnostics output file");
+            writeln!(out, "{}", json).unwrap();
+        }
+        None => println!("{}", json),
+    }
+}
+
+// human warnings go to stderr so they never mix into the output on stdout.
+// the callers have no source location yet, so json warnings carry an empty file and line 0
+pub fn warning(msg: &str) {
+    match *ERROR_FORMAT.lock().unwrap() {
+        ErrorFormat::Human => eprintln!("warning: {}", msg),
+        ErrorFormat::Json => PENDING.lock().unwrap().push(json_record("", 0, "warning", msg)),
+    }
+}
+
+// json diagnostics are written to stdout, so other output has to go elsewhere
+pub fn diagnostics_on_stdout() -> bool {
+    *ERROR_FORMAT.lock().unwrap() == ErrorFormat::Json
+        && DIAGNOSTICS_OUTPUT.lock().unwrap().is_none()
+}
+
+// write out json warnings that no error_exit has reported (an empty array when there are none);
+// call before a successful exit
+pub fn flush_diagnostics() {
+    if *ERROR_FORMAT.lock().unwrap() == ErrorFormat::Json {
+        let records: Vec<String> = PENDING.lock().unwrap().drain(..).collect();
+        emit_json(&records);
+    }
+}
+
+pub fn error_exit(file: &str, line: i32, msg: &str) -> ! {
+    match *ERROR_FORMAT.lock().unwrap() {
+        ErrorFormat::Human => println!("error: {}: {}", line, msg),
+        ErrorFormat::Json => {
+            let mut records: Vec<String> = PENDING.lock().unwrap().drain(..).collect();
+            records.push(json_record(file, line, "error", msg));
+            emit_json(&records);
+        }
+    }
     process::exit(-1);
 }
+
+// run `f` with json diagnostics on and return the warnings it buffered
+#[cfg(test)]
+pub(crate) fn json_warnings(f: impl FnOnce()) -> Vec<String> {
+    static JSON_MODE: Mutex<()> = Mutex::new(());
+    let _lock = JSON_MODE.lock().unwrap_or_else(|e| e.into_inner());
+    set_error_format(ErrorFormat::Json);
+    PENDING.lock().unwrap().clear();
+    f();
+    set_error_format(ErrorFormat::Human);
+    let warnings = PENDING.lock().unwrap().drain(..).collect();
+    warnings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_escape_specials() {
+        assert_eq!(json_escape("plain"), "plain");
+        assert_eq!(json_escape("say \"hi\""), "say \\\"hi\\\"");
+        assert_eq!(json_escape("a\\b"), "a\\\\b");
+        assert_eq!(json_escape("x\ny\tz"), "x\\ny\\tz");
+        assert_eq!(json_escape("\u{1}"), "\\u0001");
+    }
+
+    #[test]
+    fn json_warning_is_buffered() {
+        let warnings = json_warnings(|| warning("x \"y\""));
+        let expected = r#"{"file":"","line":0,"col":0,"level":"warning","message":"x \"y\""}"#;
+        assert!(warnings.iter().any(|r| r == expected), "{:?}", warnings);
+    }
+
+    #[test]
+    fn json_record_format() {
+        assert_eq!(
+            json_record("dir\\a.c", 3, "error", "expected \"]\""),
+            r#"{"file":"dir\\a.c","line":3,"col":0,"level":"error","message":"expected \"]\""}"#
+        );
+    }
+}
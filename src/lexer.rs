@@ -1,18 +1,18 @@
-use std::io::{BufRead, BufReader};
+use std::fs;
 use std::fs::OpenOptions;
-use std::io;
 use std::io::prelude::*;
 use std::iter;
 use std::str;
 use std::collections::VecDeque;
 use std::path;
-use std::process;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::cell::RefCell;
+use std::rc::Rc;
 use std::sync::{Arc, Mutex};
 use crate::error;
 
 lazy_static! {
-    static ref MacroMap: Arc<Mutex<HashMap<String, Macro>>> = Arc::new(Mutex::new(HashMap::new()));
+    static ref MACRO_MAP: Arc<Mutex<HashMap<String, Macro>>> = Arc::new(Mutex::new(HashMap::new()));
 }
 
 pub enum Macro {
@@ -29,12 +29,16 @@ pub enum TokenKind {
     Char,
     Symbol,
     Newline,
+    // comments are only produced with retain_comments; val includes the delimiters
+    LineComment,
+    BlockComment,
 }
 
 #[derive(PartialEq, Debug, Clone)]
 pub struct Token {
     pub kind: TokenKind,
     pub space: bool, // leading space
+    pub whitespace: String, // the spaces and tabs before the token, as written
     pub val: String,
     pub line: i32,
 }
@@ -44,18 +48,199 @@ impl Token {
         Token {
             kind,
             space: false,
+            whitespace: String::new(),
             val: val.to_string(),
             line,
         }
     }
 }
 
+impl Token {
+    pub fn is_comment(&self) -> bool {
+        self.kind == TokenKind::LineComment || self.kind == TokenKind::BlockComment
+    }
+
+    // the token as it is written in source
+    pub fn spelling(&self) -> String {
+        match self.kind {
+            TokenKind::String => format!("\"{}\"", self.val),
+            TokenKind::Char => format!("'{}'", self.val),
+            TokenKind::Newline => "\n".to_string(),
+            _ => self.val.clone(),
+        }
+    }
+}
+
+const KEYWORDS: &[&str] = &[
+    "auto", "break", "case", "char", "const", "continue", "default", "do", "double", "else",
+    "enum", "extern", "float", "for", "goto", "if", "inline", "int", "long", "register",
+    "restrict", "return", "short", "signed", "sizeof", "static", "struct", "switch", "typedef",
+    "union", "unsigned", "void", "volatile", "while", "_Alignas", "_Alignof", "_Atomic", "_Bool",
+    "_Complex", "_Generic", "_Imaginary", "_Noreturn", "_Static_assert", "_Thread_local",
+];
+
+pub fn is_keyword(name: &str) -> bool {
+    KEYWORDS.contains(&name)
+}
+
+// reconstruct source text from a token stream (e.g. one read with get_with_newlines)
+pub fn format_tokens(tokens: &[Token]) -> String {
+    let mut s = String::new();
+    for tok in tokens {
+        if tok.space {
+            s.push(' ');
+        }
+        s.push_str(&tok.spelling());
+    }
+    s
+}
+
+fn define_line(name: &str, body: &[Token]) -> String {
+    let mut s = format!("#define {}", name);
+    for tok in body {
+        s.push(' ');
+        s.push_str(&tok.spelling());
+    }
+    s
+}
+
+// every macro currently defined, as `#define NAME body` lines sorted by name (for -dM)
+pub fn macro_definitions() -> Vec<String> {
+    let map = MACRO_MAP.lock().unwrap();
+    let mut names: Vec<&String> = map.keys().collect();
+    names.sort();
+    names
+        .into_iter()
+        .map(|name| match map[name] {
+            Macro::Object(ref body) => define_line(name, body),
+        })
+        .collect()
+}
+
+// a type name reduced to (basic type, qualifiers per pointer level, declarator tokens)
+type CanonicalType = (Vec<String>, Vec<Vec<String>>, Vec<String>);
+
+fn canonical_type(toks: &[Token]) -> CanonicalType {
+    let mut base: Vec<String> = Vec::new();
+    let mut quals: Vec<Vec<String>> = vec![Vec::new()];
+    let mut declarator: Vec<String> = Vec::new();
+    for t in toks {
+        match t.val.as_str() {
+            "const" | "volatile" | "restrict" => quals.last_mut().unwrap().push(t.val.clone()),
+            "*" => quals.push(Vec::new()),
+            _ if t.kind == TokenKind::Identifier && quals.len() == 1 && declarator.is_empty() => {
+                base.push(t.val.clone())
+            }
+            _ => declarator.push(t.val.clone()),
+        }
+    }
+
+    // qualifiers on the outermost type do not affect compatibility
+    quals.last_mut().unwrap().clear();
+    for q in quals.iter_mut() {
+        q.sort();
+        q.dedup();
+    }
+
+    // `signed int`, `int signed` and `signed` all name int, `long int` is long, ...
+    base.sort();
+    if !base.iter().any(|w| w == "char") {
+        base.retain(|w| w != "signed");
+    }
+    if base.iter().any(|w| w == "short" || w == "long" || w == "unsigned" || w == "signed") {
+        base.retain(|w| w != "int");
+    }
+    if base.is_empty() {
+        base.push("int".to_string());
+    }
+    (base, quals, declarator)
+}
+
+// compatibility of two type names as written, for __builtin_types_compatible_p.
+// typedef names are compared by name since the lexer cannot resolve them.
+fn types_compatible(a: &[Token], b: &[Token]) -> bool {
+    canonical_type(a) == canonical_type(b)
+}
+
+// #include edges collected by a Lexer with set_dep_graph
+#[derive(Default)]
+pub struct DepGraph {
+    pub nodes: HashSet<String>,
+    pub edges: Vec<(String, String)>,
+}
+
+impl DepGraph {
+    pub fn new() -> DepGraph {
+        DepGraph::default()
+    }
+
+    // node names are normalized, so `./x.h` and `x.h` are the same node
+    pub fn add_node(&mut self, name: &str) {
+        self.nodes.insert(normalize_path(path::Path::new(name)));
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.nodes.contains(&normalize_path(path::Path::new(name)))
+    }
+
+    pub fn add_edge(&mut self, from: &str, to: &str) {
+        let from = normalize_path(path::Path::new(from));
+        let to = normalize_path(path::Path::new(to));
+        self.nodes.insert(from.clone());
+        self.nodes.insert(to.clone());
+        let edge = (from, to);
+        if !self.edges.contains(&edge) {
+            self.edges.push(edge);
+        }
+    }
+
+    // graphviz output, one `a -> b` line per edge
+    pub fn to_dot(&self) -> String {
+        let mut nodes: Vec<&String> = self.nodes.iter().collect();
+        nodes.sort();
+        let mut s = String::from("digraph deps {\n");
+        for node in nodes {
+            s.push_str(&format!("    {:?};\n", node));
+        }
+        for (from, to) in &self.edges {
+            s.push_str(&format!("    {:?} -> {:?};\n", from, to));
+        }
+        s.push_str("}\n");
+        s
+    }
+}
+
+// fold `.` and `..` components so a header reached by different relative paths has one name
+fn normalize_path(p: &path::Path) -> String {
+    let mut parts: Vec<path::Component> = Vec::new();
+    for c in p.components() {
+        match c {
+            path::Component::CurDir => {}
+            path::Component::ParentDir => match parts.last() {
+                Some(path::Component::Normal(_)) => {
+                    parts.pop();
+                }
+                _ => parts.push(c),
+            },
+            _ => parts.push(c),
+        }
+    }
+    parts.iter().collect::<path::PathBuf>().to_string_lossy().into_owned()
+}
+
 pub struct Lexer<'a> {
     cur_line: i32,
     filename: String,
     peek: iter::Peekable<str::Chars<'a>>,
     peek_buf: VecDeque<char>,
     buf: VecDeque<Token>,
+    expanded: VecDeque<Token>, // rest of a macro expansion; not expanded again
+    defines_out: Option<Box<dyn Write>>, // -dD
+    expansions_out: Option<Box<dyn Write>>, // --print-macro-expansions
+    expansion_depth: u32,
+    retain_comments: bool,
+    deps: Option<Rc<RefCell<DepGraph>>>, // record includes instead of reading them
+    deps_system: bool,                   // also scan <...> headers
 }
 
 impl<'a> Lexer<'a> {
@@ -66,6 +251,41 @@ impl<'a> Lexer<'a> {
             peek: input.chars().peekable(),
             peek_buf: VecDeque::new(),
             buf: VecDeque::new(),
+            expanded: VecDeque::new(),
+            defines_out: None,
+            expansions_out: None,
+            expansion_depth: 0,
+            retain_comments: false,
+            deps: None,
+            deps_system: false,
+        }
+    }
+
+    // record #include edges in `graph` instead of tokenizing the included files.
+    // "..." headers are scanned for their own includes; <...> headers only with `system`
+    pub fn set_dep_graph(&mut self, graph: Rc<RefCell<DepGraph>>, system: bool) {
+        self.deps = Some(graph);
+        self.deps_system = system;
+    }
+
+    // return comments as LineComment/BlockComment tokens instead of skipping them
+    pub fn set_retain_comments(&mut self, on: bool) {
+        self.retain_comments = on;
+    }
+
+    // echo #define/#undef directives to `out` as they are processed (-dD)
+    pub fn set_defines_output(&mut self, out: Box<dyn Write>) {
+        self.defines_out = Some(out);
+    }
+
+    // log every macro expansion step to `out` (--print-macro-expansions)
+    pub fn set_expansions_output(&mut self, out: Box<dyn Write>) {
+        self.expansions_out = Some(out);
+    }
+
+    fn trace_define(&mut self, line: &str) {
+        if let Some(ref mut out) = self.defines_out {
+            writeln!(out, "{}", line).unwrap();
         }
     }
 
@@ -99,38 +319,22 @@ impl<'a> Lexer<'a> {
 
     fn peek_char_is(&mut self, ch: char) -> bool {
         let line = self.cur_line;
-        let errf = || -> Option<&char> {
-            error::error_exit(line, format!("expected '{}'", ch).as_str());
-            None
-        };
-
-        let peekc = self.peek_get().or_else(errf).unwrap();
-        *peekc == ch
-    }
-
-    fn skip(&mut self, s: &str) -> bool {
-        let next = self.read_token();
-        let n = next.ok_or("error").unwrap();
-        if n.val == s && n.kind != TokenKind::String && n.kind != TokenKind::Char {
-            true
-        } else {
-            self.buf.push_back(n);
-            false
+        let filename = self.filename.clone();
+        match self.peek_get() {
+            Some(&peekc) => peekc == ch,
+            None => error::error_exit(&filename, line, format!("expected '{}'", ch).as_str()),
         }
     }
 
     fn unget(&mut self, t: Token) {
-        self.buf.push_back(t);
+        self.buf.push_front(t);
     }
 
     pub fn read_identifier(&mut self) -> Token {
         let mut ident = String::new();
-        loop {
-            match self.peek_get() {
-                Some(&c) => match c {
-                    'a'..='z' | 'A'..='Z' | '_' | '0'..='9' => ident.push(c),
-                    _ => break,
-                },
+        while let Some(&c) = self.peek_get() {
+            match c {
+                'a'..='z' | 'A'..='Z' | '_' | '0'..='9' => ident.push(c),
                 _ => break,
             };
             self.peek_next();
@@ -141,17 +345,14 @@ impl<'a> Lexer<'a> {
     fn read_number_literal(&mut self) -> Token {
         let mut num = String::new();
         let mut is_float = false;
-        loop {
-            match self.peek_get() {
-                Some(&c) => match c {
-                    '.' | '0'..='9' => {
-                        num.push(c);
-                        if c == '.' {
-                            is_float = true;
-                        }
+        while let Some(&c) = self.peek_get() {
+            match c {
+                '.' | '0'..='9' => {
+                    num.push(c);
+                    if c == '.' {
+                        is_float = true;
                     }
-                    _ => break,
-                },
+                }
                 _ => break,
             };
             self.peek_next();
@@ -165,8 +366,10 @@ impl<'a> Lexer<'a> {
 
     pub fn read_newline(&mut self) -> Token {
         self.peek_next();
+        // a newline belongs to the line it terminates
+        let tok = Token::new(TokenKind::Newline, "", self.cur_line);
         self.cur_line += 1;
-        Token::new(TokenKind::Newline, "", self.cur_line)
+        tok
     }
 
     pub fn read_symbol(&mut self) -> Token {
@@ -174,10 +377,14 @@ impl<'a> Lexer<'a> {
         let mut sym = String::new();
         sym.push(c);
         match c {
-            '+' | '-' | '*' | '/' | '%' | '=' | '^' | '!' => {
-                if self.peek_char_is('=') {
-                    sym.push(self.peek_next());
-                }
+            '+' | '-' if self.peek_char_is(c) => {
+                sym.push(self.peek_next());
+            }
+            '-' if self.peek_char_is('>') => {
+                sym.push(self.peek_next());
+            }
+            '+' | '-' | '*' | '/' | '%' | '=' | '^' | '!' if self.peek_char_is('=') => {
+                sym.push(self.peek_next());
             }
             '<' | '>' | '&' | '|' => {
                 if self.peek_char_is(c) {
@@ -187,11 +394,9 @@ impl<'a> Lexer<'a> {
                     sym.push(self.peek_next());
                 }
             }
-            '.' => {
-                if self.peek_char_is('.') && self.peek_next_char_is('.') {
-                    sym.push(self.peek_next());
-                    sym.push(self.peek_next());
-                }
+            '.' if self.peek_char_is('.') && self.peek_next_char_is('.') => {
+                sym.push(self.peek_next());
+                sym.push(self.peek_next());
             }
             _ => {}
         };
@@ -218,6 +423,35 @@ impl<'a> Lexer<'a> {
         Token::new(TokenKind::Char, &s, self.cur_line)
     }
 
+    fn read_block_comment(&mut self) -> Token {
+        let line = self.cur_line;
+        let mut s = String::new();
+        s.push(self.peek_next()); // /
+        s.push(self.peek_next()); // *
+        while !(self.peek_char_is('*') && self.peek_next_char_is('/')) {
+            let c = self.peek_next();
+            if c == '\n' {
+                self.cur_line += 1;
+            }
+            s.push(c);
+        }
+        s.push(self.peek_next());
+        s.push(self.peek_next());
+        Token::new(TokenKind::BlockComment, &s, line)
+    }
+
+    fn read_line_comment(&mut self) -> Token {
+        let mut s = String::new();
+        while let Some(&c) = self.peek_get() {
+            if c == '\n' {
+                break;
+            }
+            s.push(c);
+            self.peek_next();
+        }
+        Token::new(TokenKind::LineComment, &s, self.cur_line)
+    }
+
     pub fn do_read_token(&mut self) -> Option<Token> {
         if !self.buf.is_empty() {
             return self.buf.pop_front();
@@ -229,12 +463,11 @@ impl<'a> Lexer<'a> {
                 ' ' | '\t' => {
                     self.peek_next();
                     // set a leading space
-                    fn f(tok: Token) -> Option<Token> {
-                        let mut t = tok;
+                    self.do_read_token().map(|mut t| {
                         t.space = true;
-                        Some(t)
-                    }
-                    self.read_token().and_then(f)
+                        t.whitespace.insert(0, c);
+                        t
+                    })
                 }
                 '0'..='9' => Some(self.read_number_literal()),
                 '\"' => Some(self.read_string_literal()),
@@ -245,22 +478,21 @@ impl<'a> Lexer<'a> {
                     self.read_token()
                 }
                 '/' => {
-                    if self.peek_next_char_is('*') {
-                        self.peek_next(); // /
-                        self.peek_next(); // *
-                        while !(self.peek_char_is('*') && self.peek_next_char_is('/')) {
-                            self.peek_next();
-                        }
-                        self.peek_next();
-                        self.peek_next();
-                        self.do_read_token()
-                    } else if self.peek_next_char_is('/') {
-                        self.peek_next(); // /
-                        self.peek_next(); // /
-                        while !self.peek_char_is('\n') {
-                            self.peek_next();
+                    if self.peek_next_char_is('*') || self.peek_next_char_is('/') {
+                        let comment = if self.peek_next_char_is('*') {
+                            self.read_block_comment()
+                        } else {
+                            self.read_line_comment()
+                        };
+                        if self.retain_comments {
+                            Some(comment)
+                        } else {
+                            // a comment separates tokens like whitespace does
+                            self.do_read_token().map(|mut t| {
+                                t.space = true;
+                                t
+                            })
                         }
-                        self.do_read_token()
                     } else {
                         Some(self.read_symbol())
                     }
@@ -282,49 +514,192 @@ impl<'a> Lexer<'a> {
         }
     }
 
-    fn expand(&mut self, token: Option<Token>) -> Option<Token> {
-        token.and_then(|tok| match MacroMap.lock().unwrap().get(tok.val.as_str()) {
-            Some(a) => match a {
-                Macro::Object(ref t) => {
-                    for tt in t.iter().rev() {
-                        self.unget(tt.clone());
+    // None if `tok` is a macro that expands to nothing
+    fn expand(&mut self, tok: Token) -> Option<Token> {
+        let space = tok.space;
+        let mut expanded = self.expand_token(tok, &mut Vec::new());
+        if expanded.is_empty() {
+            return None;
+        }
+        expanded[0].space = space;
+        self.expanded.extend(expanded.drain(1..));
+        expanded.pop()
+    }
+
+    // __builtin_types_compatible_p(T1, T2) folds to an IntNumber "1" or "0"
+    fn read_types_compatible_p(&mut self, builtin: Token) -> Token {
+        let mut args: Vec<Vec<Token>> = vec![Vec::new()];
+        let mut depth = 0;
+        loop {
+            let t = match self.get() {
+                Some(t) => t,
+                None => error::error_exit(&self.filename, self.cur_line, "unexpected end of file"),
+            };
+            match t.val.as_str() {
+                "(" if t.kind == TokenKind::Symbol => {
+                    depth += 1;
+                    if depth == 1 {
+                        continue;
                     }
-                    self.read_token()
                 }
-            },
-            None => Some(tok),
-        })
+                ")" if t.kind == TokenKind::Symbol => {
+                    depth -= 1;
+                    if depth == 0 {
+                        break;
+                    }
+                }
+                "," if t.kind == TokenKind::Symbol && depth == 1 => {
+                    args.push(Vec::new());
+                    continue;
+                }
+                _ => {}
+            }
+            if depth == 0 {
+                error::error_exit(
+                    &self.filename,
+                    t.line,
+                    "expected '(' after __builtin_types_compatible_p",
+                );
+            }
+            args.last_mut().unwrap().push(t);
+        }
+        if args.len() != 2 || args.iter().any(|a| a.is_empty()) {
+            error::error_exit(
+                &self.filename,
+                builtin.line,
+                "__builtin_types_compatible_p takes two type names",
+            );
+        }
+
+        let val = if types_compatible(&args[0], &args[1]) { "1" } else { "0" };
+        let mut tok = Token::new(TokenKind::IntNumber, val, builtin.line);
+        tok.space = builtin.space;
+        tok
+    }
+
+    // fully expand `tok`; macros named in `active` are being expanded and are left alone
+    fn expand_token(&mut self, tok: Token, active: &mut Vec<String>) -> Vec<Token> {
+        if tok.kind != TokenKind::Identifier || active.contains(&tok.val) {
+            return vec![tok];
+        }
+        let body = match MACRO_MAP.lock().unwrap().get(tok.val.as_str()) {
+            Some(Macro::Object(ref body)) => body.clone(),
+            None => return vec![tok],
+        };
+        if let Some(ref mut out) = self.expansions_out {
+            writeln!(
+                out,
+                "{}[MACRO] {}: {} => {}",
+                "  ".repeat(self.expansion_depth as usize),
+                tok.val,
+                tok.val,
+                format_tokens(&body).trim_start()
+            )
+            .unwrap();
+        }
+
+        active.push(tok.val);
+        self.expansion_depth += 1;
+        let mut expanded = Vec::new();
+        for t in body {
+            expanded.extend(self.expand_token(t, active));
+        }
+        self.expansion_depth -= 1;
+        active.pop();
+        expanded
     }
 
     pub fn get(&mut self) -> Option<Token> {
-        let t = self.read_token();
-        let tok = match t {
-            Some(tok) => {
-                if tok.val == "#" {
-                    // preprocessor directive
-                    self.read_cpp_directive();
-                    self.get()
-                } else {
-                    Some(tok)
-                }
+        self.next_token(false)
+    }
+
+    // like get(), but keeps Newline tokens in the stream
+    pub fn get_with_newlines(&mut self) -> Option<Token> {
+        self.next_token(true)
+    }
+
+    // preprocess the rest of the input, then list what ended up defined (for -dM)
+    pub fn dump_macros(&mut self) -> Vec<String> {
+        while self.get().is_some() {}
+        macro_definitions()
+    }
+
+    fn next_token(&mut self, keep_newlines: bool) -> Option<Token> {
+        let tok = loop {
+            if let Some(tok) = self.expanded.pop_front() {
+                break tok;
             }
-            _ => return t,
+            let tok = if keep_newlines {
+                self.do_read_token()?
+            } else {
+                self.read_token()?
+            };
+            if tok.kind == TokenKind::Symbol && tok.val == "#" {
+                // preprocessor directive
+                self.read_cpp_directive();
+                continue;
+            }
+            if let Some(tok) = self.expand(tok) {
+                break tok;
+            }
+            // the macro expanded to nothing; read on
         };
-        self.expand(tok)
+        // checked after expansion so that a macro can stand for the builtin
+        if tok.kind == TokenKind::Identifier && tok.val == "__builtin_types_compatible_p" {
+            return Some(self.read_types_compatible_p(tok));
+        }
+        Some(tok)
     }
 
     // for c preprocessor
 
+    // directives stop before their line's Newline so get_with_newlines() still sees it
     fn read_cpp_directive(&mut self) {
         let t = self.do_read_token(); // cpp directive
+        if let Some(ref nl) = t {
+            if nl.kind == TokenKind::Newline {
+                // null directive
+                self.unget(nl.clone());
+                return;
+            }
+        }
+        if self.deps.is_some() {
+            // only includes matter when collecting dependencies
+            if t.as_ref().map(|t| t.val.as_str()) == Some("include") {
+                self.read_cpp_include();
+            } else {
+                self.skip_line();
+            }
+            return;
+        }
         match t.ok_or("error").unwrap().val.as_str() {
             "include" => self.read_cpp_include(),
             "define" => self.read_cpp_define(),
+            "undef" => self.read_cpp_undef(),
+            "embed" => self.read_cpp_embed(),
             _ => {}
         }
     }
 
-    fn cpp_try_include(&mut self, filename: &str) -> Option<String> {
+    fn skip_line(&mut self) {
+        while let Some(t) = self.do_read_token() {
+            if t.kind == TokenKind::Newline {
+                self.unget(t);
+                break;
+            }
+        }
+    }
+
+    // `system` is true for <...>; "..." is looked up next to the current file first
+    fn cpp_try_include(&mut self, filename: &str, system: bool) -> Option<String> {
+        if !system {
+            if let Some(dir) = path::Path::new(&self.filename).parent() {
+                let real_filename = dir.join(filename);
+                if real_filename.exists() {
+                    return Some(normalize_path(&real_filename));
+                }
+            }
+        }
         let header_paths = vec![
             "./include/",
             "/include/",
@@ -336,60 +711,531 @@ impl<'a> Lexer<'a> {
         for header_path in header_paths {
             let real_filename = format!("{}{}", header_path, filename);
             if path::Path::new(&real_filename).exists() {
-                return Some(real_filename);
+                return Some(normalize_path(path::Path::new(&real_filename)));
             }
         }
         None
     }
 
     fn read_cpp_include(&mut self) {
-        let mut filename = String::new();
-        if self.skip("<") {
-            while !self.peek_char_is('>') {
-                filename.push(self.peek_next());
+        let (filename, system) = match self.read_token() {
+            Some(ref t) if t.kind == TokenKind::Symbol && t.val == "<" => {
+                let mut filename = String::new();
+                while !self.peek_char_is('>') {
+                    filename.push(self.peek_next());
+                }
+                self.peek_next();
+                (filename, true)
             }
-            self.peek_next();
+            Some(ref t) if t.kind == TokenKind::String => (t.val.clone(), false),
+            _ => error::error_exit(
+                &self.filename,
+                self.cur_line,
+                "expected file name after #include",
+            ),
+        };
+        let real_filename = self.cpp_try_include(&filename, system);
+
+        if let Some(graph) = self.deps.clone() {
+            let target = real_filename.clone().unwrap_or_else(|| filename.clone());
+            let scan = real_filename.is_some()
+                && (!system || self.deps_system)
+                && !graph.borrow().contains(&target);
+            graph.borrow_mut().add_edge(&self.filename, &target);
+            if scan {
+                let body = fs::read_to_string(&target).unwrap();
+                let mut lexer = Lexer::new(target.clone(), &body);
+                lexer.set_dep_graph(graph, self.deps_system);
+                while lexer.get().is_some() {}
+            }
+            return;
         }
-        let real_filename = match self.cpp_try_include(&filename) {
+
+        let real_filename = match real_filename {
             Some(f) => f,
-            _ => {
-                eprintln!("error: {}: not found '{}'", self.cur_line, filename);
-                process::exit(-1)
-            }
+            _ => error::error_exit(
+                &self.filename,
+                self.cur_line,
+                format!("not found '{}'", filename).as_str(),
+            ),
         };
-        println!("include filename: {}", real_filename);
         let mut file = OpenOptions::new().read(true).open(&real_filename).unwrap();
         let mut body = String::new();
         file.read_to_string(&mut body).unwrap();
-        let mut lexer = Lexer::new(filename.clone(), &body);
+        let mut lexer = Lexer::new(real_filename.clone(), &body);
         while let Some(tok) = lexer.get() {
             self.buf.push_back(tok);
         }
-        println!("end of: {}", real_filename);
+    }
+
+    // #embed "file" [limit(n)] -> 0xNN, 0xNN, ...
+    fn read_cpp_embed(&mut self) {
+        // reading the parameters consumes the end of the line
+        let line = self.cur_line;
+        let (filename, system) = match self.do_read_token() {
+            Some(ref t) if t.kind == TokenKind::String => (t.val.clone(), false),
+            Some(ref t) if t.val == "<" => {
+                let mut filename = String::new();
+                while !self.peek_char_is('>') {
+                    filename.push(self.peek_next());
+                }
+                self.peek_next();
+                (filename, true)
+            }
+            _ => error::error_exit(
+                &self.filename,
+                line,
+                "expected file name after #embed",
+            ),
+        };
+
+        // trailing parameters; only limit(n) / __limit__(n) is understood
+        let mut limit: Option<usize> = None;
+        let mut params: Vec<Token> = Vec::new();
+        while let Some(t) = self.do_read_token() {
+            if t.kind == TokenKind::Newline {
+                self.unget(t);
+                break;
+            }
+            params.push(t);
+        }
+        let mut i = 0;
+        while i < params.len() {
+            let p = params[i].val.as_str();
+            if (p == "limit" || p == "__limit__")
+                && params.get(i + 1).map(|t| t.val.as_str()) == Some("(")
+                && params.get(i + 3).map(|t| t.val.as_str()) == Some(")")
+            {
+                match params[i + 2].val.parse::<usize>() {
+                    Ok(n) => limit = Some(n),
+                    Err(_) => error::error_exit(&self.filename, line, "invalid #embed limit"),
+                }
+                i += 4;
+            } else {
+                i += 1;
+            }
+        }
+
+        let real_filename = match self.cpp_try_include(&filename, system) {
+            Some(f) => f,
+            _ => error::error_exit(
+                &self.filename,
+                line,
+                format!("not found '{}'", filename).as_str(),
+            ),
+        };
+        let mut bytes = match fs::read(&real_filename) {
+            Ok(bytes) => bytes,
+            Err(_) => error::error_exit(
+                &self.filename,
+                line,
+                format!("cannot read '{}'", real_filename).as_str(),
+            ),
+        };
+        if let Some(n) = limit {
+            bytes.truncate(n);
+        }
+
+        for (i, b) in bytes.iter().enumerate() {
+            if i > 0 {
+                self.buf.push_back(Token::new(TokenKind::Symbol, ",", line));
+            }
+            let val = format!("0x{:02x}", b);
+            let mut tok = Token::new(TokenKind::IntNumber, &val, line);
+            tok.space = i > 0;
+            self.buf.push_back(tok);
+        }
+    }
+
+    fn read_macro_name(&mut self) -> Token {
+        let line = self.cur_line; // before a Newline in place of the name
+        match self.do_read_token() {
+            Some(t) if t.kind == TokenKind::Identifier => t,
+            _ => error::error_exit(&self.filename, line, "macro name missing"),
+        }
     }
 
     fn read_cpp_define(&mut self) {
-        let mcro = self.do_read_token().unwrap();
-        assert_eq!(mcro.kind, TokenKind::Identifier);
+        let mcro = self.read_macro_name();
 
         // TODO: func like macro is unsupported now..
-        if self.skip("(") {
-            error::error_exit(self.cur_line, "unsupported");
+        match self.do_read_token() {
+            Some(ref t) if t.val == "(" && !t.space => {
+                error::error_exit(&self.filename, self.cur_line, "unsupported")
+            }
+            Some(t) => self.unget(t),
+            None => {}
         }
 
-        println!("\tmacro name: {}", mcro.val);
-
         let mut body: Vec<Token> = Vec::new();
-        print!("\tmacro body: ");
-        loop {
-            let c = self.do_read_token().unwrap();
-            if c.kind == TokenKind::Newline {
-                break;
+        while let Some(c) = self.do_read_token() {
+            match c.kind {
+                TokenKind::Newline => {
+                    self.unget(c);
+                    break;
+                }
+                TokenKind::LineComment | TokenKind::BlockComment => {}
+                _ => body.push(c),
+            }
+        }
+        self.trace_define(&define_line(&mcro.val, &body));
+        MACRO_MAP.lock().unwrap().insert(mcro.val, Macro::Object(body));
+    }
+
+    fn read_cpp_undef(&mut self) {
+        let mcro = self.read_macro_name();
+        self.trace_define(&format!("#undef {}", mcro.val));
+        MACRO_MAP.lock().unwrap().remove(&mcro.val);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::MutexGuard;
+
+    // the macro table is global; tests that preprocess run one at a time on a clean table
+    static PREPROCESSOR: Mutex<()> = Mutex::new(());
+
+    fn preprocessor_lock() -> MutexGuard<'static, ()> {
+        let guard = PREPROCESSOR.lock().unwrap_or_else(|e| e.into_inner());
+        MACRO_MAP.lock().unwrap().clear();
+        guard
+    }
+
+    // a Write that tests can read back after handing it to the lexer
+    #[derive(Clone, Default)]
+    struct SharedBuf(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(data)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SharedBuf {
+        fn lines(&self) -> Vec<String> {
+            String::from_utf8(self.0.borrow().clone())
+                .unwrap()
+                .lines()
+                .map(|l| l.to_string())
+                .collect()
+        }
+    }
+
+    fn vals(tokens: &[Token]) -> Vec<&str> {
+        tokens.iter().map(|t| t.val.as_str()).collect()
+    }
+
+    fn preprocess(src: &str) -> Vec<Token> {
+        let mut lexer = Lexer::new("test.c".to_string(), src);
+        let mut tokens = Vec::new();
+        while let Some(tok) = lexer.get() {
+            tokens.push(tok);
+        }
+        tokens
+    }
+
+    fn newline_lines(src: &str) -> Vec<i32> {
+        let mut lexer = Lexer::new("test.c".to_string(), src);
+        let mut lines = Vec::new();
+        while let Some(tok) = lexer.get_with_newlines() {
+            if tok.kind == TokenKind::Newline {
+                lines.push(tok.line);
             }
-            print!("{}{}", if c.space { " " } else { "" }, c.val);
-            body.push(c);
         }
-        println!();
-        MacroMap.lock().unwrap().insert(mcro.val, Macro::Object(body));
+        lines
+    }
+
+    #[test]
+    fn get_with_newlines_keeps_newlines() {
+        let _lock = preprocessor_lock();
+        assert_eq!(newline_lines("int a;\nint b;\nc = 1;\n"), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn get_with_newlines_keeps_directive_lines() {
+        let _lock = preprocessor_lock();
+        assert_eq!(newline_lines("a\n\n#define Z 3\nZ\n"), vec![1, 2, 3, 4]);
+        assert_eq!(newline_lines("#define E\nE\nx\n"), vec![1, 2, 3]);
+        assert_eq!(newline_lines("#\n#undef Q\nx\n"), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn get_skips_newlines() {
+        let _lock = preprocessor_lock();
+        let tokens = preprocess("a\n#define Z 3\nZ\n");
+        assert_eq!(vals(&tokens), vec!["a", "3"]);
+    }
+
+    fn dump_macros(src: &str) -> Vec<String> {
+        Lexer::new("test.c".to_string(), src).dump_macros()
+    }
+
+    #[test]
+    fn dump_macros_empty_file() {
+        let _lock = preprocessor_lock();
+        assert!(dump_macros("").is_empty());
+    }
+
+    #[test]
+    fn dump_macros_lists_definitions() {
+        let _lock = preprocessor_lock();
+        assert_eq!(dump_macros("#define FOO 42\n"), vec!["#define FOO 42"]);
+        assert_eq!(
+            dump_macros("#define B x + 1\n#define A\n#undef FOO\nB\n"),
+            vec!["#define A", "#define B x + 1"]
+        );
+    }
+
+    #[test]
+    fn defines_output_in_order() {
+        let _lock = preprocessor_lock();
+        let out = SharedBuf::default();
+        let src = "#define A 1\n#define B A + 2\nB\n#undef A\n";
+        let mut lexer = Lexer::new("test.c".to_string(), src);
+        lexer.set_defines_output(Box::new(out.clone()));
+        while lexer.get().is_some() {}
+        assert_eq!(out.lines(), vec!["#define A 1", "#define B A + 2", "#undef A"]);
+    }
+
+    #[test]
+    fn embed_bytes() {
+        let _lock = preprocessor_lock();
+        let dir = std::env::temp_dir().join(format!("rcc-embed-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("data.bin"), [0x00, 0x7f, 0x80, 0xff]).unwrap();
+
+        let src = "int a[] = {\n#embed \"data.bin\"\n};\n";
+        let mut lexer = Lexer::new(dir.join("test.c").to_str().unwrap().to_string(), src);
+        let mut tokens = Vec::new();
+        while let Some(tok) = lexer.get() {
+            tokens.push(tok);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+
+        let bytes: Vec<&Token> = tokens[6..tokens.len() - 2].iter().collect();
+        assert_eq!(
+            bytes.iter().map(|t| t.val.as_str()).collect::<Vec<_>>(),
+            vec!["0x00", ",", "0x7f", ",", "0x80", ",", "0xff"]
+        );
+        assert!(bytes.iter().all(|t| t.line == 2));
+        assert_eq!(vals(&tokens[tokens.len() - 2..]), vec!["}", ";"]);
+    }
+
+    fn type_tokens(src: &str) -> Vec<Token> {
+        // read_symbol looks one character past a trailing `*`
+        let src = format!("{}\n", src);
+        let mut lexer = Lexer::new("test.c".to_string(), &src);
+        let mut tokens = Vec::new();
+        while let Some(tok) = lexer.read_token() {
+            tokens.push(tok);
+        }
+        tokens
+    }
+
+    fn compatible(a: &str, b: &str) -> bool {
+        types_compatible(&type_tokens(a), &type_tokens(b))
+    }
+
+    #[test]
+    fn types_compatible_cases() {
+        assert!(compatible("int", "int"));
+        assert!(!compatible("int", "double"));
+        assert!(compatible("int", "const int"));
+        assert!(!compatible("const int *", "int *"));
+        assert!(compatible("int * const", "int *"));
+        assert!(compatible("signed", "int"));
+        assert!(compatible("long int", "long"));
+    }
+
+    #[test]
+    fn canonical_type_drops_outer_qualifiers() {
+        let (base, quals, declarator) = canonical_type(&type_tokens("const unsigned int"));
+        assert_eq!(base, vec!["unsigned"]);
+        assert_eq!(quals, vec![Vec::<String>::new()]);
+        assert!(declarator.is_empty());
+    }
+
+    #[test]
+    fn types_compatible_p_folds() {
+        let _lock = preprocessor_lock();
+        let tokens = preprocess("__builtin_types_compatible_p(int, const int)");
+        assert_eq!(vals(&tokens), vec!["1"]);
+        let tokens = preprocess("#define T double\n__builtin_types_compatible_p(int, T)\n");
+        assert_eq!(vals(&tokens), vec!["0"]);
+    }
+
+    #[test]
+    fn types_compatible_p_from_macro() {
+        let _lock = preprocessor_lock();
+        let tokens = preprocess("#define SAME __builtin_types_compatible_p(int, int)\nx = SAME;\n");
+        assert_eq!(vals(&tokens), vec!["x", "=", "1", ";"]);
+    }
+
+    fn lex_retaining_comments(src: &str) -> Vec<(TokenKind, String)> {
+        let mut lexer = Lexer::new("test.c".to_string(), src);
+        lexer.set_retain_comments(true);
+        let mut tokens = Vec::new();
+        while let Some(tok) = lexer.do_read_token() {
+            tokens.push(tok);
+        }
+        kinds_and_vals(&tokens)
+    }
+
+    #[test]
+    fn retained_block_comments() {
+        let kv = |kind: TokenKind, val: &str| (kind, val.to_string());
+        assert_eq!(
+            lex_retaining_comments("/* block */int/* another */x;"),
+            vec![
+                kv(TokenKind::BlockComment, "/* block */"),
+                kv(TokenKind::Identifier, "int"),
+                kv(TokenKind::BlockComment, "/* another */"),
+                kv(TokenKind::Identifier, "x"),
+                kv(TokenKind::Symbol, ";"),
+            ]
+        );
+    }
+
+    #[test]
+    fn retained_line_comments() {
+        let kv = |kind: TokenKind, val: &str| (kind, val.to_string());
+        assert_eq!(
+            lex_retaining_comments("x; // note\ny; // at eof"),
+            vec![
+                kv(TokenKind::Identifier, "x"),
+                kv(TokenKind::Symbol, ";"),
+                kv(TokenKind::LineComment, "// note"),
+                kv(TokenKind::Newline, ""),
+                kv(TokenKind::Identifier, "y"),
+                kv(TokenKind::Symbol, ";"),
+                kv(TokenKind::LineComment, "// at eof"),
+            ]
+        );
+    }
+
+    #[test]
+    fn dep_graph_shares_header_node() {
+        let _lock = preprocessor_lock();
+        let dir = std::env::temp_dir().join(format!("rcc-deps-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("x.h"), "int x;\n").unwrap();
+        let sources = [("a.c", "#include \"x.h\"\n"), ("b.c", "#include \"./x.h\"\n")];
+
+        let graph = Rc::new(RefCell::new(DepGraph::new()));
+        for (name, src) in sources.iter() {
+            // reach the file through `.` to check that node names are normalized
+            let filename = dir.join(".").join(name).to_str().unwrap().to_string();
+            graph.borrow_mut().add_node(&filename);
+            let mut lexer = Lexer::new(filename, src);
+            lexer.set_dep_graph(graph.clone(), false);
+            while lexer.get().is_some() {}
+        }
+        fs::remove_dir_all(&dir).unwrap();
+
+        let d = dir.to_str().unwrap();
+        let expected = format!(
+            concat!(
+                "digraph deps {{\n",
+                "    \"{d}/a.c\";\n",
+                "    \"{d}/b.c\";\n",
+                "    \"{d}/x.h\";\n",
+                "    \"{d}/a.c\" -> \"{d}/x.h\";\n",
+                "    \"{d}/b.c\" -> \"{d}/x.h\";\n",
+                "}}\n"
+            ),
+            d = d
+        );
+        assert_eq!(graph.borrow().to_dot(), expected);
+    }
+
+    #[test]
+    fn self_referential_macro_stops() {
+        let _lock = preprocessor_lock();
+        assert_eq!(vals(&preprocess("#define A x A\nA\n")), vec!["x", "A"]);
+    }
+
+    #[test]
+    fn indirectly_self_referential_macro_stops() {
+        let _lock = preprocessor_lock();
+        let tokens = preprocess("#define A B\n#define B y A\nA B\n");
+        assert_eq!(vals(&tokens), vec!["y", "A", "y", "B"]);
+    }
+
+    #[test]
+    fn whitespace_as_written() {
+        let mut lexer = Lexer::new("test.c".to_string(), "\t\tx  \t= 1;");
+        let mut tokens = Vec::new();
+        while let Some(tok) = lexer.do_read_token() {
+            tokens.push(tok);
+        }
+        let ws: Vec<&str> = tokens.iter().map(|t| t.whitespace.as_str()).collect();
+        assert_eq!(ws, vec!["\t\t", "  \t", " ", ""]);
+    }
+
+    fn kinds_and_vals(tokens: &[Token]) -> Vec<(TokenKind, String)> {
+        tokens.iter().map(|t| (t.kind.clone(), t.val.clone())).collect()
+    }
+
+    fn lex_with_newlines(src: &str) -> Vec<Token> {
+        let mut lexer = Lexer::new("test.c".to_string(), src);
+        let mut tokens = Vec::new();
+        while let Some(tok) = lexer.get_with_newlines() {
+            tokens.push(tok);
+        }
+        tokens
+    }
+
+    #[test]
+    fn format_tokens_roundtrip() {
+        let _lock = preprocessor_lock();
+        let snippets = [
+            "int main(void) {\n    return 0;\n}\n",
+            "char *s = \"a b, c\";\nchar c = 'x';\n",
+            "i++; --j; p->next = q; f(a, ...);\nx <<= 1 && y != 2.5;\n",
+            "x/**/y\nz/* two\nlines */w // trailing\nv\n",
+        ];
+        for src in snippets.iter() {
+            let tokens = lex_with_newlines(src);
+            let formatted = format_tokens(&tokens);
+            assert_eq!(
+                kinds_and_vals(&lex_with_newlines(&formatted)),
+                kinds_and_vals(&tokens),
+                "formatted as {:?}",
+                formatted
+            );
+        }
+    }
+
+    #[test]
+    fn skipped_comment_separates_tokens() {
+        let _lock = preprocessor_lock();
+        let tokens = preprocess("x/**/y");
+        assert_eq!(vals(&tokens), vec!["x", "y"]);
+        assert!(tokens[1].space);
+        assert_eq!(format_tokens(&tokens), "x y");
+    }
+
+    #[test]
+    fn empty_macro_keeps_directives_working() {
+        let _lock = preprocessor_lock();
+        let tokens = preprocess("#define E\nE\n#define F 1\nF\n");
+        assert_eq!(vals(&tokens), vec!["1"]);
+    }
+
+    #[test]
+    fn nested_expansion_trace() {
+        let _lock = preprocessor_lock();
+        let trace = SharedBuf::default();
+        let mut lexer = Lexer::new("test.c".to_string(), "#define A B\n#define B 2\nA\n");
+        lexer.set_expansions_output(Box::new(trace.clone()));
+        assert_eq!(lexer.get().unwrap().val, "2");
+        assert_eq!(lexer.get(), None);
+        assert_eq!(trace.lines(), vec!["[MACRO] A: A => B", "  [MACRO] B: B => 2"]);
     }
 }
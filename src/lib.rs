@@ -1,6 +1,7 @@
 pub mod error;
 pub mod lexer;
 pub mod node;
+pub mod source;
 pub mod version_info;
 
 #[macro_use]
@@ -9,7 +10,7 @@ use std::collections::HashMap;
 use std::sync::Mutex;
 
 lazy_static!{
-    pub static ref MacroMap: Mutex<HashMap<String, lexer::Macro> > = {
+    pub static ref MACRO_MAP: Mutex<HashMap<String, lexer::Macro> > = {
         Mutex::new( HashMap::new() )
     };
 }
@@ -2,38 +2,83 @@ extern crate rcc;
 
 use rcc::version_info;
 use rcc::lexer;
+use rcc::error;
+use rcc::source;
 
 use std::fs::OpenOptions;
-use std::io::Read;
+use std::io;
+use std::io::Write;
 
 
 fn main() {
     let args: Vec<String> = std::env::args().collect();
 
-    if args.len() < 2 {
-        version_info::show_version();
-        version_info::show_usage();
-    } else {
-        let input_file_name = &args[1]; // Borrow the string to avoid unnecessary cloning
-
-        // Open the file and read its content
-        let mut file = OpenOptions::new()
-            .read(true)
-            .open(input_file_name)
-            .expect("Failed to open the file");
+    let mut input_file_name: Option<&String> = None;
+    let mut dump_macros = false; // -dM
+    let mut dump_defines = false; // -dD
+    let mut defines_file: Option<&String> = None; // -MF
+    let mut trace_expansions = false; // --print-macro-expansions
+    let mut iter = args[1..].iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "-dM" => dump_macros = true,
+            "-dD" => dump_defines = true,
+            "--print-macro-expansions" => trace_expansions = true,
+            "-MF" => defines_file = Some(iter.next().expect("-MF requires a file name")),
+            "--error-format=json" => error::set_error_format(error::ErrorFormat::Json),
+            "--error-format=human" => error::set_error_format(error::ErrorFormat::Human),
+            "--diagnostics-output" => error::set_diagnostics_output(
+                iter.next().expect("--diagnostics-output requires a file name"),
+            ),
+            _ => input_file_name = Some(arg),
+        }
+    }
 
-        let mut s = String::new();
-        file.read_to_string(&mut s).expect("Failed to read the file");
-        let mut lexer = lexer::Lexer::new(input_file_name.clone(), &s); 
-        // test 
-        let mut tok: Option<lexer::Token>;
-        loop {
-            match lexer.get() {
-                Some(t) => {
-                    println!("token: {}{}", if t.space { " "} else {""}, t.val);
+    match input_file_name {
+        None => {
+            version_info::show_version();
+            version_info::show_usage();
+        }
+        Some(input_file_name) => {
+            let s = source::read_source(Some(input_file_name));
+            // keep stdout for the json array when it goes there
+            let mut out: Box<dyn Write> = if error::diagnostics_on_stdout() {
+                Box::new(io::stderr())
+            } else {
+                Box::new(io::stdout())
+            };
+            let mut lexer = lexer::Lexer::new(input_file_name.clone(), &s);
+            if trace_expansions {
+                lexer.set_expansions_output(Box::new(io::stderr()));
+            }
+            if dump_defines {
+                match defines_file {
+                    Some(name) => {
+                        let out = OpenOptions::new()
+                            .write(true)
+                            .create(true)
+                            .truncate(true)
+                            .open(name)
+                            .expect("Failed to open the -MF file");
+                        lexer.set_defines_output(Box::new(out));
+                    }
+                    None if error::diagnostics_on_stdout() => {
+                        lexer.set_defines_output(Box::new(io::stderr()))
+                    }
+                    None => lexer.set_defines_output(Box::new(io::stdout())),
+                }
+            }
+            if dump_macros {
+                for line in lexer.dump_macros() {
+                    writeln!(out, "{}", line).unwrap();
+                }
+            } else {
+                // test
+                while let Some(t) = lexer.get() {
+                    writeln!(out, "token: {}{}", if t.space { " "} else {""}, t.val).unwrap();
                 }
-                None => break,
             }
+            error::flush_diagnostics();
         }
     }
 }
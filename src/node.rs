@@ -1,5 +1,6 @@
 use std::rc::Rc;
 use std::process;
+use crate::error;
 
 pub enum AST {
     Int(i32),
@@ -29,6 +30,31 @@ pub enum CBinOps {
     Shr,
 }
 
+impl CBinOps {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CBinOps::Add => "+",
+            CBinOps::Sub => "-",
+            CBinOps::Mul => "*",
+            CBinOps::Div => "/",
+            CBinOps::Rem => "%",
+            CBinOps::And => "&",
+            CBinOps::Or => "|",
+            CBinOps::Xor => "^",
+            CBinOps::LAnd => "&&",
+            CBinOps::LOr => "||",
+            CBinOps::Eq => "==",
+            CBinOps::Ne => "!=",
+            CBinOps::Lt => "<",
+            CBinOps::Gt => ">",
+            CBinOps::Le => "<=",
+            CBinOps::Ge => ">=",
+            CBinOps::Shl => "<<",
+            CBinOps::Shr => ">>",
+        }
+    }
+}
+
 pub struct BinaryOpAst {
     pub lhs: Rc<AST>,
     pub rhs: Rc<AST>,
@@ -70,38 +96,283 @@ impl BinaryOpAst {
     }
 
     pub fn eval_constexpr(&self) -> i32 {
-        let lhs = self.lhs.eval_constexpr();
-        let rhs = self.rhs.eval_constexpr();
+        self.try_eval_constexpr()
+            .unwrap_or_else(|| panic!("expression is not a valid integer constant expression"))
+    }
+
+    // None if an operand is not an integer constant, or the operation has no defined result
+    pub fn try_eval_constexpr(&self) -> Option<i32> {
+        let lhs = self.lhs.try_eval_constexpr()?;
+        let rhs = self.rhs.try_eval_constexpr()?;
+        self.apply_int(lhs, rhs)
+    }
+
+    fn apply_int(&self, lhs: i32, rhs: i32) -> Option<i32> {
+        let shift = u32::try_from(rhs).ok();
         match self.op {
-            CBinOps::Add => lhs + rhs,
-            CBinOps::Sub => lhs - rhs,
-            CBinOps::Mul => lhs * rhs,
-            CBinOps::Div => lhs / rhs,
-            CBinOps::Rem => lhs % rhs,
-            CBinOps::And => lhs & rhs,
-            CBinOps::Or => lhs | rhs,
-            CBinOps::Xor => lhs ^ rhs,
-            CBinOps::LAnd => (lhs != 0 && rhs != 0) as i32,
-            CBinOps::LOr => (lhs != 0 || rhs != 0) as i32,
-            CBinOps::Eq => (lhs == rhs) as i32,
-            CBinOps::Ne => (lhs != rhs) as i32,
-            CBinOps::Lt => (lhs < rhs) as i32,
-            CBinOps::Gt => (lhs > rhs) as i32,
-            CBinOps::Le => (lhs <= rhs) as i32,
-            CBinOps::Ge => (lhs >= rhs) as i32,
-            CBinOps::Shl => lhs << rhs,
-            CBinOps::Shr => lhs >> rhs,
+            CBinOps::Add => lhs.checked_add(rhs),
+            CBinOps::Sub => lhs.checked_sub(rhs),
+            CBinOps::Mul => lhs.checked_mul(rhs),
+            CBinOps::Div => lhs.checked_div(rhs),
+            CBinOps::Rem => lhs.checked_rem(rhs),
+            CBinOps::And => Some(lhs & rhs),
+            CBinOps::Or => Some(lhs | rhs),
+            CBinOps::Xor => Some(lhs ^ rhs),
+            CBinOps::LAnd => Some((lhs != 0 && rhs != 0) as i32),
+            CBinOps::LOr => Some((lhs != 0 || rhs != 0) as i32),
+            CBinOps::Eq => Some((lhs == rhs) as i32),
+            CBinOps::Ne => Some((lhs != rhs) as i32),
+            CBinOps::Lt => Some((lhs < rhs) as i32),
+            CBinOps::Gt => Some((lhs > rhs) as i32),
+            CBinOps::Le => Some((lhs <= rhs) as i32),
+            CBinOps::Ge => Some((lhs >= rhs) as i32),
+            CBinOps::Shl => shift.and_then(|n| lhs.checked_shl(n)),
+            CBinOps::Shr => shift.and_then(|n| lhs.checked_shr(n)),
+        }
+    }
+
+    // IEEE 754 arithmetic: x/0.0 is +-inf, 0.0/0.0 is NaN and NaN compares unequal to everything.
+    // like C, only an operation with a floating operand is done in floating point
+    pub fn eval_constexpr_float(&self) -> f64 {
+        self.try_eval_constexpr_float()
+            .unwrap_or_else(|| panic!("expression is not a valid floating-point constant expression"))
+    }
+
+    // None if an operand is not a constant, or the operation is not defined on its operands
+    pub fn try_eval_constexpr_float(&self) -> Option<f64> {
+        let lhs = self.lhs.try_eval_constexpr_float()?;
+        let rhs = self.rhs.try_eval_constexpr_float()?;
+        if !self.lhs.is_float() && !self.rhs.is_float() {
+            // both operands are ints, so the values are exact
+            return self.apply_int(lhs as i32, rhs as i32).map(|n| n as f64);
+        }
+        let truth = |b: bool| Some(if b { 1.0 } else { 0.0 });
+        match self.op {
+            CBinOps::Add => Some(lhs + rhs),
+            CBinOps::Sub => Some(lhs - rhs),
+            CBinOps::Mul => Some(lhs * rhs),
+            CBinOps::Div => {
+                if rhs == 0.0 {
+                    error::warning(
+                        "division by zero in floating-point constant [-Wfloat-zero-divide]",
+                    );
+                    if lhs == 0.0 || lhs.is_nan() {
+                        Some(f64::NAN)
+                    } else if lhs.is_sign_negative() == rhs.is_sign_negative() {
+                        Some(f64::INFINITY)
+                    } else {
+                        Some(f64::NEG_INFINITY)
+                    }
+                } else {
+                    Some(lhs / rhs)
+                }
+            }
+            CBinOps::LAnd => truth(lhs != 0.0 && rhs != 0.0),
+            CBinOps::LOr => truth(lhs != 0.0 || rhs != 0.0),
+            CBinOps::Eq => truth(lhs == rhs),
+            CBinOps::Ne => truth(lhs != rhs),
+            CBinOps::Lt => truth(lhs < rhs),
+            CBinOps::Gt => truth(lhs > rhs),
+            CBinOps::Le => truth(lhs <= rhs),
+            CBinOps::Ge => truth(lhs >= rhs),
+            // %, bitwise operators and shifts take no floating operand
+            _ => None,
         }
     }
 }
 
 impl AST {
     pub fn eval_constexpr(&self) -> i32 {
+        self.try_eval_constexpr()
+            .unwrap_or_else(|| panic!("expression is not a valid integer constant expression"))
+    }
+
+    // e.g. for array dimensions: Some(n) for an integer constant expression,
+    // None when it depends on a variable (a VLA) or cannot be evaluated
+    pub fn try_eval_constexpr(&self) -> Option<i32> {
+        match self {
+            AST::Int(n) => Some(*n),
+            AST::BinaryOp(ref bin) => bin.try_eval_constexpr(),
+            _ => None,
+        }
+    }
+
+    // whether the expression has a floating type; comparisons and && / || give an int
+    pub fn is_float(&self) -> bool {
+        match self {
+            AST::Float(_) => true,
+            AST::BinaryOp(ref bin) => match bin.op {
+                CBinOps::Add | CBinOps::Sub | CBinOps::Mul | CBinOps::Div => {
+                    bin.lhs.is_float() || bin.rhs.is_float()
+                }
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+
+    pub fn eval_constexpr_float(&self) -> f64 {
+        self.try_eval_constexpr_float()
+            .unwrap_or_else(|| panic!("expression is not a valid floating-point constant expression"))
+    }
+
+    pub fn try_eval_constexpr_float(&self) -> Option<f64> {
+        match self {
+            AST::Int(n) => Some(*n as f64),
+            AST::Float(f) => Some(*f),
+            AST::BinaryOp(ref bin) => bin.try_eval_constexpr_float(),
+            _ => None,
+        }
+    }
+
+    // fold a floating-point constant expression into an AST::Float (which may be inf or NaN)
+    pub fn fold_constexpr_float(&self) -> Option<AST> {
+        self.try_eval_constexpr_float().map(AST::Float)
+    }
+
+    // S-expression dump, e.g. (BinaryOp "+" (Int 1) (Variable "x"))
+    pub fn pretty_print(&self) -> String {
         match self {
-            AST::Int(n) => *n,
-            AST::BinaryOp(ref bin) => bin.eval_constexpr(),
-            _ => panic!("Invalid AST node for constant expression evaluation"),
+            AST::Int(n) => format!("(Int {})", n),
+            AST::Float(f) => format!("(Float {:?})", f),
+            AST::Variable(ref name) => format!("(Variable {:?})", name),
+            AST::BinaryOp(ref bin) => format!(
+                "(BinaryOp {:?} {} {})",
+                bin.op.as_str(),
+                bin.lhs.pretty_print(),
+                bin.rhs.pretty_print()
+            ),
         }
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bin(lhs: AST, op: &str, rhs: AST) -> AST {
+        AST::BinaryOp(BinaryOpAst::new(Rc::new(lhs), Rc::new(rhs), op.to_string()))
+    }
+
+    #[test]
+    fn try_eval_constexpr_int() {
+        assert_eq!(bin(AST::Int(3), "+", AST::Int(4)).try_eval_constexpr(), Some(7));
+        assert_eq!(bin(AST::Int(7), "<", AST::Int(4)).try_eval_constexpr(), Some(0));
+        assert_eq!(bin(AST::Int(1), "<<", AST::Int(4)).try_eval_constexpr(), Some(16));
+    }
+
+    #[test]
+    fn try_eval_constexpr_not_constant() {
+        assert_eq!(AST::Variable("n".to_string()).try_eval_constexpr(), None);
+        let vla = bin(AST::Variable("n".to_string()), "*", AST::Int(2));
+        assert_eq!(vla.try_eval_constexpr(), None);
+    }
+
+    #[test]
+    fn try_eval_constexpr_undefined() {
+        assert_eq!(bin(AST::Int(1), "/", AST::Int(0)).try_eval_constexpr(), None);
+        assert_eq!(bin(AST::Int(1), "%", AST::Int(0)).try_eval_constexpr(), None);
+        assert_eq!(bin(AST::Int(i32::MIN), "/", AST::Int(-1)).try_eval_constexpr(), None);
+        assert_eq!(bin(AST::Int(1), "<<", AST::Int(32)).try_eval_constexpr(), None);
+        assert_eq!(bin(AST::Int(1), "<<", AST::Int(-1)).try_eval_constexpr(), None);
+    }
+
+    fn eval_float(lhs: f64, op: &str, rhs: f64) -> f64 {
+        bin(AST::Float(lhs), op, AST::Float(rhs)).eval_constexpr_float()
+    }
+
+    #[test]
+    fn eval_constexpr_float_division_by_zero() {
+        assert_eq!(eval_float(1.0, "/", 0.0), f64::INFINITY);
+        assert_eq!(eval_float(-1.0, "/", 0.0), f64::NEG_INFINITY);
+        assert_eq!(eval_float(1.0, "/", -0.0), f64::NEG_INFINITY);
+        assert!(eval_float(0.0, "/", 0.0).is_nan());
+        assert_eq!(eval_float(3.0, "/", 2.0), 1.5);
+    }
+
+    #[test]
+    fn eval_constexpr_float_integer_operands() {
+        let half = bin(AST::Int(1), "/", AST::Int(2));
+        assert_eq!(bin(half, "*", AST::Float(2.0)).eval_constexpr_float(), 0.0);
+        assert_eq!(bin(AST::Int(7), "/", AST::Int(2)).eval_constexpr_float(), 3.0);
+        assert_eq!(bin(AST::Int(7), "/", AST::Float(2.0)).eval_constexpr_float(), 3.5);
+        let lt = bin(AST::Float(1.5), "<", AST::Float(2.0));
+        assert_eq!(bin(lt, "/", AST::Int(2)).eval_constexpr_float(), 0.0);
+    }
+
+    #[test]
+    fn try_eval_constexpr_float_not_constant() {
+        assert_eq!(bin(AST::Int(1), "/", AST::Int(0)).try_eval_constexpr_float(), None);
+        let x = || AST::Variable("x".to_string());
+        assert_eq!(x().try_eval_constexpr_float(), None);
+        assert_eq!(bin(x(), "+", AST::Float(1.0)).try_eval_constexpr_float(), None);
+        assert_eq!(bin(AST::Float(5.0), "%", AST::Int(2)).try_eval_constexpr_float(), None);
+        assert!(bin(x(), "*", AST::Float(2.0)).fold_constexpr_float().is_none());
+    }
+
+    #[test]
+    fn fold_constexpr_float_keeps_inf_and_nan() {
+        match bin(AST::Float(1.0), "/", AST::Float(0.0)).fold_constexpr_float() {
+            Some(AST::Float(f)) => assert_eq!(f, f64::INFINITY),
+            _ => panic!("expected an AST::Float"),
+        }
+        match bin(AST::Float(-1.0), "/", AST::Int(0)).fold_constexpr_float() {
+            Some(AST::Float(f)) => assert_eq!(f, f64::NEG_INFINITY),
+            _ => panic!("expected an AST::Float"),
+        }
+        match bin(AST::Float(0.0), "/", AST::Float(0.0)).fold_constexpr_float() {
+            Some(AST::Float(f)) => assert!(f.is_nan()),
+            _ => panic!("expected an AST::Float"),
+        }
+    }
+
+    #[test]
+    fn float_zero_divide_warns() {
+        let warnings = error::json_warnings(|| {
+            bin(AST::Float(1.0), "/", AST::Float(0.0)).fold_constexpr_float();
+        });
+        let expected = concat!(
+            r#"{"file":"","line":0,"col":0,"level":"warning","#,
+            r#""message":"division by zero in floating-point constant [-Wfloat-zero-divide]"}"#
+        );
+        assert!(warnings.iter().any(|w| w == expected), "{:?}", warnings);
+    }
+
+    #[test]
+    fn eval_constexpr_float_nan_compares_unequal() {
+        let nan = || bin(AST::Float(0.0), "/", AST::Float(0.0));
+        assert_eq!(bin(nan(), "==", nan()).eval_constexpr_float(), 0.0);
+        assert_eq!(bin(nan(), "!=", nan()).eval_constexpr_float(), 1.0);
+        assert_eq!(eval_float(f64::NAN, "!=", f64::NAN), 1.0);
+        assert_eq!(eval_float(f64::NAN, "<", 1.0), 0.0);
+    }
+
+    #[test]
+    fn pretty_print_leaves() {
+        assert_eq!(AST::Int(-3).pretty_print(), "(Int -3)");
+        assert_eq!(AST::Float(2.5).pretty_print(), "(Float 2.5)");
+        assert_eq!(AST::Float(1.0).pretty_print(), "(Float 1.0)");
+        assert_eq!(AST::Variable("x".to_string()).pretty_print(), "(Variable \"x\")");
+    }
+
+    #[test]
+    fn pretty_print_nested() {
+        let sum = bin(AST::Int(1), "+", AST::Variable("x".to_string()));
+        assert_eq!(sum.pretty_print(), "(BinaryOp \"+\" (Int 1) (Variable \"x\"))");
+        let nested = bin(sum, "<<", bin(AST::Float(0.5), "*", AST::Int(2)));
+        assert_eq!(
+            nested.pretty_print(),
+            concat!(
+                "(BinaryOp \"<<\" (BinaryOp \"+\" (Int 1) (Variable \"x\")) ",
+                "(BinaryOp \"*\" (Float 0.5) (Int 2)))"
+            )
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "not a valid integer constant expression")]
+    fn eval_constexpr_panics_on_variable() {
+        AST::Variable("n".to_string()).eval_constexpr();
+    }
+}
@@ -0,0 +1,21 @@
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Read;
+
+// the contents of `filename`, or of stdin when there is none
+pub fn read_source(filename: Option<&str>) -> String {
+    let mut s = String::new();
+    match filename {
+        Some(name) => {
+            let mut file = OpenOptions::new()
+                .read(true)
+                .open(name)
+                .expect("Failed to open the file");
+            file.read_to_string(&mut s).expect("Failed to read the file");
+        }
+        None => {
+            io::stdin().read_to_string(&mut s).expect("Failed to read stdin");
+        }
+    }
+    s
+}
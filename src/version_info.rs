@@ -5,5 +5,5 @@ pub fn show_version() {
 }
 
 pub fn show_usage() {
-    println!("Usage: rcc <input_file>");
+    println!("Usage: rcc [-dM] [-dD [-MF file]] [--print-macro-expansions] [--error-format=json [--diagnostics-output file]] <input_file>");
 }
@@ -0,0 +1,27 @@
+use std::fs;
+use std::process::Command;
+
+// run rcc on `src` and return its stdout; the run has to fail
+fn rcc_error(name: &str, src: &str) -> String {
+    let dir = std::env::temp_dir().join(format!("rcc-directive-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join(name);
+    fs::write(&path, src).unwrap();
+    let out = Command::new(env!("CARGO_BIN_EXE_rcc")).arg(&path).output().unwrap();
+    assert!(!out.status.success());
+    assert!(!String::from_utf8(out.stderr).unwrap().contains("panicked"));
+    String::from_utf8(out.stdout).unwrap()
+}
+
+#[test]
+fn undef_without_name() {
+    assert_eq!(rcc_error("eof.c", "#undef"), "error: 1: macro name missing\n");
+    assert_eq!(rcc_error("newline.c", "#undef\nint a;\n"), "error: 1: macro name missing\n");
+    assert!(rcc_error("number.c", "int a;\n#undef 3\n").ends_with("error: 2: macro name missing\n"));
+}
+
+#[test]
+fn define_without_name() {
+    assert_eq!(rcc_error("define.c", "#define\n"), "error: 1: macro name missing\n");
+    assert_eq!(rcc_error("define3.c", "#define 3 4\n"), "error: 1: macro name missing\n");
+}
@@ -0,0 +1,56 @@
+use std::fs;
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+fn source_file(name: &str, src: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("rcc-json-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join(name);
+    fs::write(&path, src).unwrap();
+    path
+}
+
+fn rcc(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_rcc")).args(args).output().unwrap()
+}
+
+#[test]
+fn json_error_is_the_only_stdout() {
+    let path = source_file("bad.c", "int a;\n#embed\n");
+    let out = rcc(&["--error-format=json", path.to_str().unwrap()]);
+    assert!(!out.status.success());
+    let expected = format!(
+        concat!(
+            r#"[{{"file":"{}","line":2,"col":0,"level":"error","#,
+            r#""message":"expected file name after #embed"}}]"#,
+            "\n"
+        ),
+        path.to_str().unwrap()
+    );
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), expected);
+    assert!(String::from_utf8(out.stderr).unwrap().contains("token: int"));
+}
+
+#[test]
+fn json_success_prints_empty_array() {
+    let path = source_file("good.c", "int a;\n");
+    let out = rcc(&["--error-format=json", path.to_str().unwrap()]);
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), "[]\n");
+}
+
+#[test]
+fn json_diagnostics_output_file() {
+    let path = source_file("bad2.c", "#embed <\n");
+    let diagnostics = path.with_extension("json");
+    let out = rcc(&[
+        "--error-format=json",
+        "--diagnostics-output",
+        diagnostics.to_str().unwrap(),
+        path.to_str().unwrap(),
+    ]);
+    assert!(!out.status.success());
+    let json = fs::read_to_string(&diagnostics).unwrap();
+    assert!(json.starts_with(r#"[{"file":""#), "{}", json);
+    assert!(json.contains(r#""level":"error""#), "{}", json);
+}